@@ -1,18 +1,63 @@
 #![feature(macro_rules)]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::{Reader, Writer};
+#[cfg(test)]
+use std::io::{BufReader, MemWriter};
 
+const SHDW_MAGIC: [u8, ..4] = [ 'S' as u8, 'H' as u8, 'D' as u8, 'W' as u8 ];
+// v2 adds the page size to the header so a reload can size its pages to
+// match, now that page size is a construction-time parameter.
+const SHDW_VERSION: u8 = 2;
+
+// Default page size used by `ShadowManager::new()`; any power of two of at
+// least 8 can be chosen instead via `ShadowManager::with_page_size`.
 const PAGE_SIZE: uint = 0x1000;
 const MASK: [u8, ..8] = [ 0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80 ];
 
-macro_rules! align_x(($inp:expr, $alp:expr) => (($inp) & (0 - $alp)))
-macro_rules! align_next_x(($inp:expr, $alp:expr) => (($inp + ($alp - 1)) & (0 - $alp)))
-macro_rules! align_8(($inp:expr) => (align_x!($inp, 8)))
-macro_rules! align_next_8(($inp:expr) => (align_next_x!($inp, 8)))
+fn is_pow2(n: uint) -> bool {
+    n != 0 && (n & (n - 1)) == 0
+}
+
+fn align_down(value: uint, alignment: uint) -> uint {
+    assert!(is_pow2(alignment));
+    value & !(alignment - 1)
+}
+
+fn align_up(value: uint, alignment: uint) -> uint {
+    align_down(value + (alignment - 1), alignment)
+}
 
 struct ShadowPage {
-    buf: [u8, ..PAGE_SIZE],
-    map: [u8, ..PAGE_SIZE / 8],
+    buf: Vec<u8>,
+    map: Vec<u8>,
+}
+
+impl ShadowPage {
+    fn new(page_size: uint) -> ShadowPage {
+        ShadowPage {
+            buf: Vec::from_elem(page_size, 0u8),
+            map: Vec::from_elem(page_size / 8, 0u8),
+        }
+    }
+}
+
+impl Clone for ShadowPage {
+    fn clone(&self) -> ShadowPage {
+        ShadowPage { buf: self.buf.clone(), map: self.map.clone() }
+    }
+}
+
+fn count_ones_u8(b: u8) -> uint {
+    let mut count = 0u;
+    let mut v = b;
+
+    while v != 0 {
+        count += (v & 1) as uint;
+        v >>= 1;
+    }
+
+    count
 }
 
 impl ShadowPage {
@@ -29,7 +74,7 @@ impl ShadowPage {
         let mut i = beg;
 
         while i <= end {
-            let i_aligned = align_8!(i);
+            let i_aligned = align_down(i, 8);
             if self.map[i_aligned / 8] > 0 {
                 let bit_idx = i - i_aligned;
                 if self.map[i_aligned / 8] & MASK[bit_idx] != 0 {
@@ -39,7 +84,7 @@ impl ShadowPage {
                 }
             } else {
                 i += 1;
-                i = align_next_8!(i);
+                i = align_up(i, 8);
             }
         }
 
@@ -47,14 +92,149 @@ impl ShadowPage {
     }
 }
 
+/// A read-only backing image `ShadowManager` reads through to for any byte
+/// it hasn't patched. An emulator or VM plugs its program memory in here
+/// and lets `ShadowManager` act as a writable overlay on top of it.
+trait BaseImage {
+    fn read_byte(&self, offset: u64) -> u8;
+}
+
+/// Byte order for the multi-byte `read_u*`/`write_u*` accessors.
+enum Endian {
+    BigEndian,
+    LittleEndian,
+}
+
 struct ShadowManager {
     pages: HashMap<u64, ShadowPage>,
+    page_size: uint,
+}
+
+/// Walks every patched byte in ascending offset order, skipping whole map
+/// words at a time: a zero word advances past 8 bytes in one step, and a
+/// non-zero word is scanned bit-by-bit only within that word.
+struct PatchIter<'a> {
+    sm: &'a ShadowManager,
+    page_offsets: Vec<u64>,
+    page_idx: uint,
+    word_idx: uint,
+    bit_idx: uint,
+}
+
+impl<'a> PatchIter<'a> {
+    fn new(sm: &'a ShadowManager) -> PatchIter<'a> {
+        let mut page_offsets: Vec<u64> = sm.pages.keys().map(|x| *x).collect();
+        page_offsets.sort();
+
+        PatchIter {
+            sm: sm,
+            page_offsets: page_offsets,
+            page_idx: 0,
+            word_idx: 0,
+            bit_idx: 0,
+        }
+    }
+}
+
+impl<'a> Iterator<(u64, u8)> for PatchIter<'a> {
+    fn next(&mut self) -> Option<(u64, u8)> {
+        loop {
+            if self.page_idx >= self.page_offsets.len() {
+                return None;
+            }
+
+            let page_offs = self.page_offsets[self.page_idx];
+            let page = self.sm.pages.get(&page_offs).unwrap();
+
+            if self.word_idx >= page.map.len() {
+                self.page_idx += 1;
+                self.word_idx = 0;
+                self.bit_idx = 0;
+                continue;
+            }
+
+            let word = page.map[self.word_idx];
+
+            if word == 0 {
+                self.word_idx += 1;
+                self.bit_idx = 0;
+                continue;
+            }
+
+            while self.bit_idx < 8 {
+                let bit_idx = self.bit_idx;
+                self.bit_idx += 1;
+
+                if word & MASK[bit_idx] != 0 {
+                    let rel_offs = self.word_idx * 8 + bit_idx;
+                    return Some((page_offs + rel_offs as u64, page.buf[rel_offs]));
+                }
+            }
+
+            self.word_idx += 1;
+            self.bit_idx = 0;
+        }
+    }
+}
+
+/// Coalesces the offsets from a `PatchIter` into contiguous `(start, end)`
+/// runs (inclusive), merging bits that happen to span a page boundary since
+/// the underlying offsets are already in ascending absolute order.
+struct RangeIter<'a> {
+    patches: PatchIter<'a>,
+    next_patch: Option<(u64, u8)>,
+}
+
+impl<'a> RangeIter<'a> {
+    fn new(sm: &'a ShadowManager) -> RangeIter<'a> {
+        let mut patches = PatchIter::new(sm);
+        let next_patch = patches.next();
+
+        RangeIter { patches: patches, next_patch: next_patch }
+    }
+}
+
+impl<'a> Iterator<(u64, u64)> for RangeIter<'a> {
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let start = match self.next_patch {
+            Some((offs, _)) => offs,
+            None => return None,
+        };
+
+        let mut end = start;
+        self.next_patch = self.patches.next();
+
+        loop {
+            match self.next_patch {
+                Some((offs, _)) if offs == end + 1 => {
+                    end = offs;
+                    self.next_patch = self.patches.next();
+                }
+                _ => break,
+            }
+        }
+
+        Some((start, end))
+    }
 }
 
 impl ShadowManager {
     fn new() -> ShadowManager {
+        ShadowManager::with_page_size(PAGE_SIZE)
+    }
+
+    /// Builds a `ShadowManager` that shadows memory in `page_size`-byte
+    /// pages instead of the default. `page_size` must be a power of two, so
+    /// page-offset masking can use an explicit `page_size - 1` mask rather
+    /// than relying on unsigned wraparound.
+    fn with_page_size(page_size: uint) -> ShadowManager {
+        assert!(is_pow2(page_size), "page size must be a power of two");
+        assert!(page_size >= 8 && page_size % 8 == 0,
+            "page size must be at least 8 and a multiple of 8, since the bitmap is sized page_size / 8");
+
         ShadowManager {
             pages: HashMap::new(),
+            page_size: page_size,
         }
     }
 
@@ -64,7 +244,7 @@ impl ShadowManager {
         let (_, bit_idx) = self.get_bit_index(rel_offs);
 
         if ! self.pages.contains_key(&page_offs) {
-            let nsp = ShadowPage { buf: [0, ..PAGE_SIZE], map: [0, ..PAGE_SIZE / 8] };
+            let nsp = ShadowPage::new(self.page_size);
             self.pages.insert(page_offs, nsp);
         }
 
@@ -87,7 +267,7 @@ impl ShadowManager {
     }
 
     fn debug_dump_page(&self, page: &ShadowPage) {
-        for i in range(0, PAGE_SIZE) {
+        for i in range(0, page.buf.len()) {
             print!("{:02x} ", page.buf[i]);
         }
 
@@ -133,7 +313,7 @@ impl ShadowManager {
                 on_first_page = false;
 
                 let rel_from = beg - cur_page;
-                if sm.has_patch_in_range((rel_from as uint, PAGE_SIZE)) {
+                if sm.has_patch_in_range((rel_from as uint, self.page_size - 1)) {
                     return true;
                 }
             }
@@ -143,29 +323,395 @@ impl ShadowManager {
                 return sm.has_patch_in_range((0, rel_to as uint));
             }
 
-            cur_page += PAGE_SIZE as u64;
+            cur_page += self.page_size as u64;
         }
     }
 
     fn debug_dump_offsets(&self) {
-        for (offset, _) in self.pages.iter() {
-            for i in range(0, PAGE_SIZE) {
-                let abs_offs = *offset + i as u64;
-
-                if self.has_patch(abs_offs) {
-                    println!("got patch @ {:x}", abs_offs);
-                }
-            }
+        for (abs_offs, _) in self.iter_patches() {
+            println!("got patch @ {:x}", abs_offs);
         }
     }
 
     fn get_page_offset(&self, offset: u64) -> u64 {
-        offset & (-1 as u64 - PAGE_SIZE as u64 + 1)
+        offset & !(self.page_size as u64 - 1)
     }
 
     fn get_bit_index(&self, rel_offs: uint) -> (uint, uint) {
         (rel_offs / 8, (rel_offs % 8) as uint)
     }
+
+    /// Iterates over every patched `(abs_offset, byte)` in ascending order,
+    /// scanning the underlying bitmaps a word at a time instead of probing
+    /// every offset in every page.
+    fn iter_patches<'a>(&'a self) -> PatchIter<'a> {
+        PatchIter::new(self)
+    }
+
+    /// Iterates over contiguous patched `(start, end)` ranges (inclusive),
+    /// built on top of `iter_patches` by coalescing adjacent offsets.
+    fn iter_ranges<'a>(&'a self) -> RangeIter<'a> {
+        RangeIter::new(self)
+    }
+
+    /// Writes this patch set as: a 4-byte magic, a 1-byte version, the
+    /// 8-byte page size, an 8-byte page count, then per page the 8-byte page
+    /// offset, the `page_size / 8`-byte bitmap and only the bytes the bitmap
+    /// marks as live, in bitmap order. Pages whose
+    /// bitmap is entirely zero are skipped, so the on-disk size tracks the
+    /// number of real patches rather than the address range touched.
+    fn save<W: Writer>(&self, w: &mut W) {
+        let live_pages: Vec<(&u64, &ShadowPage)> = self.pages.iter()
+            .filter(|&(_, page)| page.map.iter().any(|&word| word != 0))
+            .collect();
+
+        w.write(SHDW_MAGIC).unwrap();
+        w.write_u8(SHDW_VERSION).unwrap();
+        w.write_be_u64(self.page_size as u64).unwrap();
+        w.write_be_u64(live_pages.len() as u64).unwrap();
+
+        for &(page_offs, page) in live_pages.iter() {
+            w.write_be_u64(*page_offs).unwrap();
+            w.write(page.map.as_slice()).unwrap();
+
+            for i in range(0, page.map.len()) {
+                let word = page.map[i];
+                if word == 0 {
+                    continue;
+                }
+
+                for b in range(0, 8) {
+                    if word & MASK[b] != 0 {
+                        w.write_u8(page.buf[i * 8 + b]).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reconstructs a `ShadowManager` from the format written by `save`,
+    /// zero-filling each page's buffer and scattering the packed bytes back
+    /// to their positions according to the bitmap.
+    fn load<R: Reader>(r: &mut R) -> ShadowManager {
+        let magic = r.read_exact(4).unwrap();
+        assert!(magic.as_slice() == SHDW_MAGIC.as_slice());
+
+        let version = r.read_u8().unwrap();
+        assert!(version == SHDW_VERSION,
+            "unsupported shadowmap format version {} (expected {})", version, SHDW_VERSION);
+        let page_size = r.read_be_u64().unwrap() as uint;
+        let page_count = r.read_be_u64().unwrap();
+
+        let mut sm = ShadowManager::with_page_size(page_size);
+
+        for _ in range(0u64, page_count) {
+            let page_offs = r.read_be_u64().unwrap();
+
+            let mut page = ShadowPage::new(page_size);
+            let map_bytes = r.read_exact(page_size / 8).unwrap();
+            for i in range(0, page_size / 8) {
+                page.map[i] = map_bytes[i];
+            }
+
+            for i in range(0, page_size / 8) {
+                let word = page.map[i];
+                if word == 0 {
+                    continue;
+                }
+
+                for b in range(0, 8) {
+                    if word & MASK[b] != 0 {
+                        page.buf[i * 8 + b] = r.read_u8().unwrap();
+                    }
+                }
+            }
+
+            sm.pages.insert(page_offs, page);
+        }
+
+        sm
+    }
+
+    /// Returns the patched byte at `offset` if one exists, otherwise reads
+    /// through to `base`. This is the single point every multi-byte read
+    /// accessor below is built on.
+    fn read_byte<B: BaseImage>(&self, offset: u64, base: &B) -> u8 {
+        if self.has_patch(offset) {
+            let page_offs = self.get_page_offset(offset);
+            let rel_offs = (offset - page_offs) as uint;
+            self.pages.get(&page_offs).unwrap().buf[rel_offs]
+        } else {
+            base.read_byte(offset)
+        }
+    }
+
+    fn read_u16<B: BaseImage>(&self, offset: u64, base: &B, endian: Endian) -> u16 {
+        let b0 = self.read_byte(offset, base) as u16;
+        let b1 = self.read_byte(offset + 1, base) as u16;
+
+        match endian {
+            BigEndian => (b0 << 8) | b1,
+            LittleEndian => (b1 << 8) | b0,
+        }
+    }
+
+    fn read_u32<B: BaseImage>(&self, offset: u64, base: &B, endian: Endian) -> u32 {
+        let b0 = self.read_byte(offset, base) as u32;
+        let b1 = self.read_byte(offset + 1, base) as u32;
+        let b2 = self.read_byte(offset + 2, base) as u32;
+        let b3 = self.read_byte(offset + 3, base) as u32;
+
+        match endian {
+            BigEndian => (b0 << 24) | (b1 << 16) | (b2 << 8) | b3,
+            LittleEndian => (b3 << 24) | (b2 << 16) | (b1 << 8) | b0,
+        }
+    }
+
+    fn read_u64<B: BaseImage>(&self, offset: u64, base: &B, endian: Endian) -> u64 {
+        let b0 = self.read_byte(offset, base) as u64;
+        let b1 = self.read_byte(offset + 1, base) as u64;
+        let b2 = self.read_byte(offset + 2, base) as u64;
+        let b3 = self.read_byte(offset + 3, base) as u64;
+        let b4 = self.read_byte(offset + 4, base) as u64;
+        let b5 = self.read_byte(offset + 5, base) as u64;
+        let b6 = self.read_byte(offset + 6, base) as u64;
+        let b7 = self.read_byte(offset + 7, base) as u64;
+
+        match endian {
+            BigEndian =>
+                (b0 << 56) | (b1 << 48) | (b2 << 40) | (b3 << 32) |
+                (b4 << 24) | (b5 << 16) | (b6 << 8) | b7,
+            LittleEndian =>
+                (b7 << 56) | (b6 << 48) | (b5 << 40) | (b4 << 32) |
+                (b3 << 24) | (b2 << 16) | (b1 << 8) | b0,
+        }
+    }
+
+    fn write_u16(&mut self, offset: u64, value: u16, endian: Endian) {
+        let (b0, b1) = match endian {
+            BigEndian => ((value >> 8) as u8, value as u8),
+            LittleEndian => (value as u8, (value >> 8) as u8),
+        };
+
+        self.add_byte(offset, b0);
+        self.add_byte(offset + 1, b1);
+    }
+
+    fn write_u32(&mut self, offset: u64, value: u32, endian: Endian) {
+        let bytes = match endian {
+            BigEndian => [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8],
+            LittleEndian => [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8],
+        };
+
+        for i in range(0, 4) {
+            self.add_byte(offset + i as u64, bytes[i]);
+        }
+    }
+
+    fn write_u64(&mut self, offset: u64, value: u64, endian: Endian) {
+        let bytes = match endian {
+            BigEndian => [
+                (value >> 56) as u8, (value >> 48) as u8, (value >> 40) as u8, (value >> 32) as u8,
+                (value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8,
+            ],
+            LittleEndian => [
+                value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8,
+                (value >> 32) as u8, (value >> 40) as u8, (value >> 48) as u8, (value >> 56) as u8,
+            ],
+        };
+
+        for i in range(0, 8) {
+            self.add_byte(offset + i as u64, bytes[i]);
+        }
+    }
+
+    /// Patches a contiguous slice starting at `offset` in one call,
+    /// splitting across page boundaries the same way individual `add_byte`
+    /// calls already do.
+    fn add_bytes(&mut self, offset: u64, bytes: &[u8]) {
+        for i in range(0, bytes.len()) {
+            self.add_byte(offset + i as u64, bytes[i]);
+        }
+    }
+
+    /// Clears the map bit for a single patched byte. If the page's bitmap
+    /// becomes entirely zero as a result, the page is dropped from `pages`
+    /// so the structure stays sparse.
+    fn remove_patch(&mut self, offset: u64) {
+        let page_offs = self.get_page_offset(offset);
+        let rel_offs = (offset - page_offs) as uint;
+        let (byte_idx, bit_idx) = self.get_bit_index(rel_offs);
+
+        let is_empty = match self.pages.get_mut(&page_offs) {
+            Some(page) => {
+                page.map[byte_idx] &= !(1 << bit_idx);
+                page.map.iter().all(|&word| word == 0)
+            }
+            None => return,
+        };
+
+        if is_empty {
+            self.pages.remove(&page_offs);
+        }
+    }
+
+    /// Clears every byte in `(beg, end)` inclusive, reclaiming any page that
+    /// ends up with an all-zero bitmap. Unlike calling `remove_patch` once
+    /// per byte, the per-byte work here is just a mask clear; the (much
+    /// more expensive) all-zero bitmap scan only runs once per page touched,
+    /// after the whole range has been cleared.
+    fn del_range(&mut self, (beg, end): (u64, u64)) {
+        let mut touched_pages: HashSet<u64> = HashSet::new();
+        let mut offset = beg;
+
+        while offset <= end {
+            let page_offs = self.get_page_offset(offset);
+            let rel_offs = (offset - page_offs) as uint;
+            let (byte_idx, bit_idx) = self.get_bit_index(rel_offs);
+
+            match self.pages.get_mut(&page_offs) {
+                Some(page) => {
+                    page.map[byte_idx] &= !(1 << bit_idx);
+                    touched_pages.insert(page_offs);
+                }
+                None => {}
+            }
+
+            offset += 1;
+        }
+
+        for page_offs in touched_pages.iter() {
+            let is_empty = match self.pages.get(page_offs) {
+                Some(page) => page.map.iter().all(|&word| word == 0),
+                None => false,
+            };
+
+            if is_empty {
+                self.pages.remove(page_offs);
+            }
+        }
+    }
+
+    /// Merges `other`'s patches into `self`, page by page. Pages present in
+    /// both managers are combined word-by-word: the map bitmaps are OR'd
+    /// together, and wherever `other`'s map has a bit set, `other`'s byte
+    /// wins (so `other` overrides `self` on overlapping patches). Pages
+    /// present only in `other` are cloned wholesale.
+    fn union_with(&mut self, other: &ShadowManager) {
+        assert!(self.page_size == other.page_size, "page sizes must match");
+
+        for (page_offs, other_page) in other.pages.iter() {
+            let mut needs_insert = false;
+
+            match self.pages.get_mut(page_offs) {
+                Some(page) => {
+                    for i in range(0, other_page.map.len()) {
+                        let other_word = other_page.map[i];
+                        if other_word == 0 {
+                            continue;
+                        }
+
+                        for b in range(0, 8) {
+                            if other_word & MASK[b] != 0 {
+                                let idx = i * 8 + b;
+                                page.buf[idx] = other_page.buf[idx];
+                            }
+                        }
+
+                        page.map[i] |= other_word;
+                    }
+                }
+                None => { needs_insert = true; }
+            }
+
+            if needs_insert {
+                self.pages.insert(*page_offs, other_page.clone());
+            }
+        }
+    }
+
+    /// Keeps only the bytes patched in both `self` and `other`, word-by-word
+    /// over the map bitmaps (`map[i] &= other_page.map[i]`). Pages with no
+    /// counterpart in `other`, and pages whose merged bitmap becomes all
+    /// zero, are dropped from `pages` entirely.
+    fn intersect_with(&mut self, other: &ShadowManager) {
+        assert!(self.page_size == other.page_size, "page sizes must match");
+
+        let mut to_remove = Vec::new();
+
+        for (page_offs, page) in self.pages.iter_mut() {
+            match other.pages.get(page_offs) {
+                Some(other_page) => {
+                    let mut any_set = false;
+
+                    for i in range(0, page.map.len()) {
+                        page.map[i] &= other_page.map[i];
+                        if page.map[i] != 0 {
+                            any_set = true;
+                        }
+                    }
+
+                    if !any_set {
+                        to_remove.push(*page_offs);
+                    }
+                }
+                None => { to_remove.push(*page_offs); }
+            }
+        }
+
+        for page_offs in to_remove.iter() {
+            self.pages.remove(page_offs);
+        }
+    }
+
+    /// Clears every map bit that `other` has patched, word-by-word
+    /// (`map[i] &= !other_page.map[i]`); the buffer bytes under the cleared
+    /// bits are left as they are. Pages whose bitmap
+    /// becomes all zero are dropped from `pages`.
+    fn subtract(&mut self, other: &ShadowManager) {
+        assert!(self.page_size == other.page_size, "page sizes must match");
+
+        let mut to_remove = Vec::new();
+
+        for (page_offs, page) in self.pages.iter_mut() {
+            match other.pages.get(page_offs) {
+                Some(other_page) => {
+                    let mut any_set = false;
+
+                    for i in range(0, page.map.len()) {
+                        page.map[i] &= !other_page.map[i];
+                        if page.map[i] != 0 {
+                            any_set = true;
+                        }
+                    }
+
+                    if !any_set {
+                        to_remove.push(*page_offs);
+                    }
+                }
+                None => {}
+            }
+        }
+
+        for page_offs in to_remove.iter() {
+            self.pages.remove(page_offs);
+        }
+    }
+
+    /// Total number of patched bytes across all pages, computed by summing
+    /// `count_ones()` over every map word rather than re-checking each byte
+    /// through `has_patch`.
+    fn patched_count(&self) -> u64 {
+        let mut count = 0u64;
+
+        for (_, page) in self.pages.iter() {
+            for i in range(0, page.map.len()) {
+                count += count_ones_u8(page.map[i]) as u64;
+            }
+        }
+
+        count
+    }
 }
 
 fn main() {
@@ -250,13 +796,329 @@ fn test_1() {
     assert!(sm.has_patch((1000)) == true);
 }
 
-#[test] fn align_0() { assert!(align_8!(0u) == 0u); }
-#[test] fn align_1() { assert!(align_8!(1u) == 0u); }
-#[test] fn align_2() { assert!(align_8!(2u) == 0u); }
-#[test] fn align_3() { assert!(align_8!(3u) == 0u); }
-#[test] fn align_4() { assert!(align_8!(4u) == 0u); }
-#[test] fn align_5() { assert!(align_8!(5u) == 0u); }
-#[test] fn align_6() { assert!(align_8!(6u) == 0u); }
-#[test] fn align_7() { assert!(align_8!(7u) == 0u); }
-#[test] fn align_8() { assert!(align_8!(8u) == 8u); }
-#[test] fn align_9() { assert!(align_8!(9u) == 8u); }
+#[test] fn align_0() { assert!(align_down(0u, 8) == 0u); }
+#[test] fn align_1() { assert!(align_down(1u, 8) == 0u); }
+#[test] fn align_2() { assert!(align_down(2u, 8) == 0u); }
+#[test] fn align_3() { assert!(align_down(3u, 8) == 0u); }
+#[test] fn align_4() { assert!(align_down(4u, 8) == 0u); }
+#[test] fn align_5() { assert!(align_down(5u, 8) == 0u); }
+#[test] fn align_6() { assert!(align_down(6u, 8) == 0u); }
+#[test] fn align_7() { assert!(align_down(7u, 8) == 0u); }
+#[test] fn align_8() { assert!(align_down(8u, 8) == 8u); }
+#[test] fn align_9() { assert!(align_down(9u, 8) == 8u); }
+
+#[test]
+fn test_with_page_size_rejects_non_pow2() {
+    let result = std::task::try(proc() { ShadowManager::with_page_size(100); });
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_with_page_size_rejects_too_small() {
+    let result = std::task::try(proc() { ShadowManager::with_page_size(4); });
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_with_page_size_512() {
+    let mut sm = ShadowManager::with_page_size(512);
+    sm.add_byte(0, 1);
+    sm.add_byte(511, 1);
+    sm.add_byte(512, 1);
+
+    assert!(sm.has_patch(0) == true);
+    assert!(sm.has_patch(511) == true);
+    assert!(sm.has_patch(512) == true);
+    assert!(sm.pages.len() == 2);
+}
+
+#[test]
+fn test_patched_count_0() {
+    let sm = ShadowManager::new();
+    assert!(sm.patched_count() == 0);
+}
+
+#[test]
+fn test_patched_count_1() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(0, 1);
+    sm.add_byte(1, 2);
+    sm.add_byte(0x2000, 3);
+    assert!(sm.patched_count() == 3);
+}
+
+#[test]
+fn test_union_disjoint_pages() {
+    let mut a = ShadowManager::new();
+    let mut b = ShadowManager::new();
+    a.add_byte(0, 1);
+    b.add_byte(0x2000, 2);
+
+    a.union_with(&b);
+
+    assert!(a.has_patch(0) == true);
+    assert!(a.has_patch(0x2000) == true);
+    assert!(a.patched_count() == 2);
+}
+
+#[test]
+fn test_union_other_overrides_self() {
+    let mut a = ShadowManager::new();
+    let mut b = ShadowManager::new();
+    a.add_byte(100, 1);
+    b.add_byte(100, 2);
+
+    a.union_with(&b);
+
+    assert!(a.has_patch(100) == true);
+    assert!(a.read_byte(100, &ZeroImage) == 2);
+    assert!(a.patched_count() == 1);
+}
+
+#[test]
+fn test_intersect_with() {
+    let mut a = ShadowManager::new();
+    let mut b = ShadowManager::new();
+    a.add_byte(100, 1);
+    a.add_byte(200, 1);
+    b.add_byte(100, 2);
+
+    a.intersect_with(&b);
+
+    assert!(a.has_patch(100) == true);
+    assert!(a.has_patch(200) == false);
+    assert!(a.patched_count() == 1);
+}
+
+#[test]
+fn test_intersect_drops_empty_page() {
+    let mut a = ShadowManager::new();
+    let mut b = ShadowManager::new();
+    a.add_byte(100, 1);
+
+    a.intersect_with(&b);
+
+    assert!(a.pages.len() == 0);
+}
+
+#[test]
+fn test_has_patch_in_range_last_byte_of_page() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(PAGE_SIZE as u64 - 1, 1);
+    assert!(sm.has_patch_in_range((0, PAGE_SIZE as u64 - 1)) == true);
+}
+
+#[test]
+fn test_iter_patches_order() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(100, 0xaa);
+    sm.add_byte(5, 0xbb);
+    sm.add_byte(0x2000, 0xcc);
+
+    let patches: Vec<(u64, u8)> = sm.iter_patches().collect();
+
+    assert!(patches == vec!((5u64, 0xbbu8), (100u64, 0xaau8), (0x2000u64, 0xccu8)));
+}
+
+#[test]
+fn test_iter_patches_empty() {
+    let sm = ShadowManager::new();
+    let patches: Vec<(u64, u8)> = sm.iter_patches().collect();
+    assert!(patches.len() == 0);
+}
+
+#[test]
+fn test_iter_ranges_coalesces() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(10, 1);
+    sm.add_byte(11, 1);
+    sm.add_byte(12, 1);
+    sm.add_byte(20, 1);
+
+    let ranges: Vec<(u64, u64)> = sm.iter_ranges().collect();
+
+    assert!(ranges == vec!((10u64, 12u64), (20u64, 20u64)));
+}
+
+#[test]
+fn test_iter_ranges_across_page_boundary() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(PAGE_SIZE as u64 - 1, 1);
+    sm.add_byte(PAGE_SIZE as u64, 1);
+
+    let ranges: Vec<(u64, u64)> = sm.iter_ranges().collect();
+
+    assert!(ranges == vec!((PAGE_SIZE as u64 - 1, PAGE_SIZE as u64)));
+}
+
+struct ZeroImage;
+
+impl BaseImage for ZeroImage {
+    fn read_byte(&self, _offset: u64) -> u8 {
+        0
+    }
+}
+
+#[test]
+fn test_read_byte_falls_through_to_base() {
+    let sm = ShadowManager::new();
+    let base = ZeroImage;
+    assert!(sm.read_byte(100, &base) == 0);
+}
+
+#[test]
+fn test_read_byte_sees_patch() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(100, 0xff);
+    let base = ZeroImage;
+    assert!(sm.read_byte(100, &base) == 0xff);
+}
+
+#[test]
+fn test_write_read_u16_roundtrip() {
+    let mut sm = ShadowManager::new();
+    let base = ZeroImage;
+
+    sm.write_u16(0, 0x1234, BigEndian);
+    assert!(sm.read_u16(0, &base, BigEndian) == 0x1234);
+
+    sm.write_u16(0, 0x1234, LittleEndian);
+    assert!(sm.read_u16(0, &base, LittleEndian) == 0x1234);
+}
+
+#[test]
+fn test_write_read_u32_roundtrip() {
+    let mut sm = ShadowManager::new();
+    let base = ZeroImage;
+
+    sm.write_u32(0, 0xdeadbeef, BigEndian);
+    assert!(sm.read_u32(0, &base, BigEndian) == 0xdeadbeef);
+
+    sm.write_u32(0, 0xdeadbeef, LittleEndian);
+    assert!(sm.read_u32(0, &base, LittleEndian) == 0xdeadbeef);
+}
+
+#[test]
+fn test_write_read_u64_roundtrip() {
+    let mut sm = ShadowManager::new();
+    let base = ZeroImage;
+
+    sm.write_u64(0, 0x0011223344556677, BigEndian);
+    assert!(sm.read_u64(0, &base, BigEndian) == 0x0011223344556677);
+
+    sm.write_u64(0, 0x0011223344556677, LittleEndian);
+    assert!(sm.read_u64(0, &base, LittleEndian) == 0x0011223344556677);
+}
+
+#[test]
+fn test_add_bytes() {
+    let mut sm = ShadowManager::new();
+    sm.add_bytes(10, [1, 2, 3].as_slice());
+    assert!(sm.has_patch(10) == true);
+    assert!(sm.has_patch(11) == true);
+    assert!(sm.has_patch(12) == true);
+    assert!(sm.has_patch(13) == false);
+}
+
+#[test]
+fn test_add_bytes_across_page_boundary() {
+    let mut sm = ShadowManager::new();
+    let start = PAGE_SIZE as u64 - 1;
+    sm.add_bytes(start, [1, 2].as_slice());
+    assert!(sm.has_patch(start) == true);
+    assert!(sm.has_patch(start + 1) == true);
+}
+
+#[test]
+fn test_remove_patch() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(100, 1);
+    sm.remove_patch(100);
+    assert!(sm.has_patch(100) == false);
+}
+
+#[test]
+fn test_remove_patch_reclaims_empty_page() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(100, 1);
+    sm.remove_patch(100);
+    assert!(sm.pages.len() == 0);
+}
+
+#[test]
+fn test_remove_patch_keeps_nonempty_page() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(100, 1);
+    sm.add_byte(200, 1);
+    sm.remove_patch(100);
+    assert!(sm.pages.len() == 1);
+    assert!(sm.has_patch(200) == true);
+}
+
+#[test]
+fn test_del_range() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(10, 1);
+    sm.add_byte(11, 1);
+    sm.add_byte(12, 1);
+    sm.add_byte(20, 1);
+
+    sm.del_range((10, 12));
+
+    assert!(sm.has_patch(10) == false);
+    assert!(sm.has_patch(11) == false);
+    assert!(sm.has_patch(12) == false);
+    assert!(sm.has_patch(20) == true);
+}
+
+#[test]
+fn test_save_load_roundtrip() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(0, 0xde);
+    sm.add_byte(100, 0xad);
+    sm.add_byte(0x2123, 0xa1);
+
+    let mut w = MemWriter::new();
+    sm.save(&mut w);
+
+    let bytes = w.unwrap();
+    let mut r = BufReader::new(bytes.as_slice());
+    let loaded = ShadowManager::load(&mut r);
+
+    assert!(loaded.has_patch(0) == true);
+    assert!(loaded.has_patch(100) == true);
+    assert!(loaded.has_patch(0x2123) == true);
+    assert!(loaded.has_patch(1) == false);
+    assert!(loaded.patched_count() == sm.patched_count());
+}
+
+#[test]
+fn test_save_skips_empty_pages() {
+    let mut sm = ShadowManager::new();
+    sm.add_byte(100, 1);
+    sm.pages.insert(0x5000, ShadowPage::new(PAGE_SIZE));
+
+    let mut w = MemWriter::new();
+    sm.save(&mut w);
+
+    let bytes = w.unwrap();
+    let mut r = BufReader::new(bytes.as_slice());
+    let loaded = ShadowManager::load(&mut r);
+
+    assert!(loaded.pages.len() == 1);
+}
+
+#[test]
+fn test_subtract() {
+    let mut a = ShadowManager::new();
+    let mut b = ShadowManager::new();
+    a.add_byte(100, 1);
+    a.add_byte(200, 1);
+    b.add_byte(100, 2);
+
+    a.subtract(&b);
+
+    assert!(a.has_patch(100) == false);
+    assert!(a.has_patch(200) == true);
+    assert!(a.patched_count() == 1);
+}